@@ -0,0 +1,184 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//!
+//! An async view of the [`RingBuffer`] for single-threaded tokio pipelines.
+//!
+//! Where the blocking producer/consumer park an OS thread, these wrappers park
+//! a *task*: when a side cannot make progress it stores its [`Waker`] in the
+//! buffer and returns `Poll::Pending`, and the opposing side wakes it once it
+//! has made space or supplied data. Because the buffer is strictly SPSC a single
+//! waker slot per side is enough. Implementing [`AsyncWrite`]/[`AsyncRead`] makes
+//! the buffer a drop-in elastic target for [`tokio::io::copy`], so a program can
+//! interpose a multi-megabyte buffer between two stages without dedicating two
+//! OS threads to it.
+
+use std::cmp;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use ringbuffer::RingBuffer;
+
+// Scratch size for moving bytes between the buffer and a `ReadBuf`.
+const SCRATCH_SIZE: usize = 1024 * 64;
+
+/// Creates a buffer of (at least) `size` bytes and returns its async writing
+/// and reading halves, mirroring [`RingBuffer::split`] for the thread-based API.
+pub fn pipe(size: usize) -> (AsyncProducer, AsyncConsumer) {
+    let shared = Arc::new(RingBuffer::new(size));
+    (AsyncProducer { shared: shared.clone() }, AsyncConsumer { shared })
+}
+
+/// The async writing half of a [`RingBuffer`]; implements [`AsyncWrite`].
+pub struct AsyncProducer {
+    shared: Arc<RingBuffer>,
+}
+
+/// The async reading half of a [`RingBuffer`]; implements [`AsyncRead`].
+pub struct AsyncConsumer {
+    shared: Arc<RingBuffer>,
+}
+
+impl AsyncWrite for AsyncProducer {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        // Fast path: there is room right now.
+        let n = self.shared.put(buf);
+        if n > 0 {
+            self.shared.wake_consumer_task();
+            return Poll::Ready(Ok(n));
+        }
+        // Full: park this task, then re-check so a drain racing the park is not
+        // lost.
+        self.shared.register_producer_waker(cx.waker());
+        let n = self.shared.put(buf);
+        if n > 0 {
+            self.shared.wake_consumer_task();
+            Poll::Ready(Ok(n))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        self.shared.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for AsyncConsumer {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+        let cap = cmp::min(buf.remaining(), SCRATCH_SIZE);
+        if cap == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let mut scratch = [0u8; SCRATCH_SIZE];
+
+        // Fast path: there is data right now. `closed` is sampled before each
+        // drain so that, once set, every byte pushed before the close is seen.
+        let closed = self.shared.is_closed();
+        let n = self.shared.get(&mut scratch[..cap]);
+        if n > 0 {
+            buf.put_slice(&scratch[..n]);
+            self.shared.wake_producer_task();
+            return Poll::Ready(Ok(()));
+        }
+        if closed {
+            return Poll::Ready(Ok(())); // EOF: leave the buffer unfilled
+        }
+        // Empty: park, then re-check so a push racing the park is not lost.
+        self.shared.register_consumer_waker(cx.waker());
+        let closed = self.shared.is_closed();
+        let n = self.shared.get(&mut scratch[..cap]);
+        if n > 0 {
+            buf.put_slice(&scratch[..n]);
+            self.shared.wake_producer_task();
+            Poll::Ready(Ok(()))
+        } else if closed {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pipe;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use tokio::io::AsyncWrite;
+
+    // Drives a future to completion by busy-polling with a no-op waker. This
+    // crate is edition 2015, so the tests cannot use `async`/`.await`; `copy`
+    // needs no reactor, only repeated polling, so a hand-rolled step is enough.
+    fn step<F: Future>(f: &mut Pin<Box<F>>, cx: &mut Context) -> Option<F::Output> {
+        match f.as_mut().poll(cx) {
+            Poll::Ready(v) => Some(v),
+            Poll::Pending => None,
+        }
+    }
+
+    #[test]
+    fn copy_roundtrips_through_async_buffer() {
+        // A payload many times the buffer size so the write side hits backpressure
+        // and both halves must make progress cooperatively.
+        let payload: Vec<u8> = (0..8192u32).map(|i| (i % 251) as u8).collect();
+        let (mut producer, mut consumer) = pipe(64);
+
+        let waker = std::task::Waker::noop();
+        let mut cx = Context::from_waker(waker);
+
+        let mut src = &payload[..];
+        let mut out = Vec::new();
+        let mut reader = Box::pin(tokio::io::copy(&mut consumer, &mut out));
+
+        // Interleave the writer and reader until the writer drains its source,
+        // then signal EOF and let the reader finish.
+        {
+            let mut writer = Box::pin(tokio::io::copy(&mut src, &mut producer));
+            loop {
+                if let Some(r) = step(&mut writer, &mut cx) { r.unwrap(); break; }
+                step(&mut reader, &mut cx);
+            }
+        }
+        Pin::new(&mut producer).poll_shutdown(&mut cx).is_ready();
+        loop {
+            if let Some(r) = step(&mut reader, &mut cx) { r.unwrap(); break; }
+        }
+        drop(reader);
+
+        assert_eq!(out, payload);
+    }
+}
@@ -0,0 +1,42 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//!
+//! A large, elastic buffer for smoothing out "lumpy" data flow. The `pipebuffer`
+//! binary sandwiches one of these between two pipes, but the same buffer is
+//! available as a library: call [`ringbuffer::RingBuffer::split`] to obtain a
+//! [`ringbuffer::Producer`]/[`ringbuffer::Consumer`] pair and interpose a
+//! multi-megabyte elastic buffer between two in-process stages without shelling
+//! out.
+//!
+//! With the `async` feature enabled, [`async_buffer`] offers `AsyncRead` /
+//! `AsyncWrite` wrappers so the same buffer can be interposed in a
+//! single-threaded tokio pipeline.
+//!
+
+#[cfg(feature = "async")]
+extern crate tokio;
+
+pub mod ringbuffer;
+
+#[cfg(feature = "async")]
+pub mod async_buffer;
@@ -27,22 +27,27 @@
 //! pipe in order to smooth out any "lumpiness" in the flow of data.
 //! 
 
-mod ringbuffer;
-
 #[macro_use] extern crate clap;
 extern crate regex;
+extern crate pipebuffer;
+
+mod stats;
 
 use std::io;
 use std::io::{Read,Write};
-use std::sync::{Arc, Mutex, Condvar};
 use std::thread;
+use std::time::Duration;
 use clap::{Arg, App};
-use ringbuffer::RingBuffer;
+use pipebuffer::ringbuffer::{RingBuffer, Producer, Consumer};
 use regex::Regex;
+use stats::Stats;
 
 // How big should the thread-local buffers for the reader and writer threads be
 const THREAD_BUFFER_SIZE: usize = 1024 * 64;
 
+// Size of the little-endian length header prepended to each record in --framed mode
+const HEADER_SIZE: usize = 4;
+
 /// Main function that coordinates argument parsing and then delegates to the
 /// `run()` function to do the actual work.
 pub fn main() {
@@ -54,18 +59,78 @@ pub fn main() {
                      .short("s").long("size")
                      .help("The size, in bytes or with k[b]/m[b]/g[b] suffix.")
                      .default_value("256m"))
+            .arg(Arg::with_name("framed")
+                     .long("framed")
+                     .help("Preserve message boundaries: frame each input read() with a 4-byte little-endian length header and never split a record across writes."))
+            .arg(Arg::with_name("coalesce")
+                     .long("coalesce")
+                     .help("Batch up queued data below this many bytes into a single write(), to avoid tiny writes when the producer trickles.")
+                     .default_value("0"))
+            .arg(Arg::with_name("low-watermark")
+                     .long("low-watermark")
+                     .help("The writer waits until at least this many bytes are buffered (or EOF) before emitting.")
+                     .default_value("0"))
+            .arg(Arg::with_name("high-watermark")
+                     .long("high-watermark")
+                     .help("The reader pauses until the buffer drains below this many bytes. Defaults to the buffer size."))
+            .arg(Arg::with_name("stats")
+                     .long("stats")
+                     .help("Periodically report throughput and buffer fill level on stderr."))
+            .arg(Arg::with_name("stats-interval")
+                     .long("stats-interval")
+                     .help("Milliseconds between --stats reports.")
+                     .default_value("1000"))
             .get_matches();
 
-    let buffer_size = match parse_memory(matches.value_of("size").unwrap()) {
+    let buffer_size = size_arg(&matches, "size");
+    let options = Options {
+        buffer_size,
+        framed:         matches.is_present("framed"),
+        coalesce:       size_arg(&matches, "coalesce"),
+        low_watermark:  size_arg(&matches, "low-watermark"),
+        // A missing high watermark means "never throttle below the buffer size".
+        high_watermark: matches.value_of("high-watermark")
+                               .map_or(buffer_size, |_| size_arg(&matches, "high-watermark")),
+        stats:          matches.is_present("stats"),
+        stats_interval: matches.value_of("stats-interval").unwrap()
+                               .parse().unwrap_or(1000),
+    };
+
+    run(options);
+}
+
+/// Parses a size-valued argument, printing usage and exiting if it is not a
+/// valid size.
+fn size_arg(matches: &clap::ArgMatches, name: &str) -> usize {
+    let value = matches.value_of(name).unwrap();
+    match parse_memory(value) {
         Some(size) => size,
         None       => {
             println!("{}", matches.usage());
-            println!("Error: Argument {} is not a valid size.", matches.value_of("size").unwrap());
+            println!("Error: Argument {} is not a valid size.", value);
             std::process::exit(1)
         }
-    };
+    }
+}
 
-    run(buffer_size);
+/// The knobs that control a single `run()` of the buffer.
+struct Options {
+    /// Requested backing-store size in bytes.
+    buffer_size: usize,
+    /// Whether to preserve record boundaries with length framing.
+    framed: bool,
+    /// Coalesce queued data below this many bytes into one write.
+    coalesce: usize,
+    /// Emit only once at least this many bytes are buffered (or EOF). Clamped to
+    /// the buffer capacity in `run()`, since a larger value can never be reached.
+    low_watermark: usize,
+    /// Throttle the reader once the buffer fills to this many bytes. Clamped to
+    /// the buffer capacity in `run()`.
+    high_watermark: usize,
+    /// Whether to emit periodic throughput statistics on stderr.
+    stats: bool,
+    /// Milliseconds between statistics reports.
+    stats_interval: u64,
 }
 
 /// Parses memory unit values from strings. Specifically accepts any value
@@ -84,92 +149,237 @@ fn parse_memory(s: &str) -> Option<usize> {
                 Some("p") => 4,
                 _         => 0
             };
-            num.map(|n| n * (1024 as usize).pow(exp))
+            num.map(|n| n * 1024usize.pow(exp))
         }
     }
 }
 
 /// Funtion that uses a pair of threads to move data from Stdin to Stdout
-/// with a RungBuffer in the middle.
-fn run(buffer_size: usize) {
-    // The shared ring buffer and the thread handles
-    let ring = Arc::new(Mutex::new(RingBuffer::new(buffer_size)));
-    let cond = Arc::new(Condvar::new());
+/// with a RungBuffer in the middle. When `framed` is set the stream is treated
+/// as a sequence of records rather than an undifferentiated byte stream.
+fn run(options: Options) {
+    // The shared ring buffer, split into the writing and reading halves that
+    // the reader and writer threads own respectively.
+    let (producer, consumer) = RingBuffer::new(options.buffer_size).split();
+    let framed = options.framed;
+    // The buffer can never hold more than its capacity, so a watermark beyond
+    // that is unsatisfiable and would park both threads forever; clamp both.
+    let capacity = producer.capacity();
+    let low = std::cmp::min(options.low_watermark, capacity);
+    let high = std::cmp::min(options.high_watermark, capacity);
+    let coalesce = std::cmp::min(options.coalesce, capacity);
+
+    // The reader stops pushing once the buffer holds `high` bytes, so a writer
+    // that waits for more than that before draining would park forever. Rather
+    // than hang silently, bail out with a clear message.
+    if low > high {
+        writeln!(&mut io::stderr(),
+                 "pipebuffer: --low-watermark ({}) exceeds --high-watermark ({}); the writer would wait for data the reader is throttled from buffering.",
+                 low, high).unwrap();
+        std::process::exit(1);
+    }
+
+    // Shared throughput counters, plus the monitor thread when --stats is on.
+    let stats = Stats::new(producer.capacity());
+    let monitor_handle = if options.stats {
+        Some(stats::spawn(stats.clone(), Duration::from_millis(options.stats_interval)))
+    } else {
+        None
+    };
 
-    // Setup the writer thread
+    // Setup the writer thread, draining the consumer half onto stdout
     let writer_handle = {
-        let ring = ring.clone();
-        let cond = cond.clone();
+        let stats = stats.clone();
         thread::spawn(move || {
-            let mut bytes: [u8; THREAD_BUFFER_SIZE] = [0; THREAD_BUFFER_SIZE];
             let mut output = io::stdout();
-            'main_loop : loop {
-                let n = {
-                    // Lock the buffer, but wait on it if it's empty
-                    let mut buffer = ring.lock().unwrap();
-                    while buffer.is_empty() {
-                        if buffer.is_closed() { break 'main_loop; }
-                        else { buffer = cond.wait(buffer).unwrap(); }
-                    }
-
-                    // Fetch from the buffer, and notify writers if we went from full to not full
-                    let was_full = buffer.is_full();
-                    let n = buffer.get(&mut bytes);
-                    if was_full && n > 0 { cond.notify_one(); }
-                    n
-                }; // lock released here
-
-                // Write the data, if any, to stdout
-                let mut start = 0;
-                while start < n { start += output.write(&bytes[start..n]).unwrap(); }
-                output.flush().unwrap();
-            }
+            if framed { write_framed(&consumer, &mut output, &stats); }
+            else       { write_stream(&consumer, &mut output, low, coalesce, &stats); }
         })
     };
 
-    // Setup this thread as the reader thread
-    let mut bytes: [u8; THREAD_BUFFER_SIZE] = [0; THREAD_BUFFER_SIZE];
+    // Setup this thread as the reader thread, feeding stdin into the producer
     let mut input = io::stdin();
+    if framed { read_framed(&producer, &mut input, &stats); }
+    else       { read_stream(&producer, &mut input, high, &stats); }
+
+    writer_handle.join().unwrap();
+
+    // Let the monitor print one last line and wind down.
+    stats.finish();
+    if let Some(handle) = monitor_handle { handle.join().unwrap(); }
+}
+
+/// Reader loop for the default byte-stream mode: forward every read into the
+/// buffer until stdin closes, throttling once the fill level reaches the high
+/// watermark.
+fn read_stream<R: Read>(producer: &Producer, input: &mut R, high: usize, stats: &Stats) {
+    let mut bytes: [u8; THREAD_BUFFER_SIZE] = [0; THREAD_BUFFER_SIZE];
     loop {
         let n = input.read(&mut bytes).unwrap();
-        let mut buffer = ring.lock().unwrap();
-        
         if n == 0 { // input stream is closed
-            buffer.close();
-            cond.notify_one();
-            break; 
+            producer.close();
+            break;
         }
         else {
-            let mut start = 0;
-            while start < n {
-                while buffer.is_full() {
-                    buffer = cond.wait(buffer).unwrap();
-                }
-                let was_empty = buffer.is_empty();
-                start += buffer.put(&bytes[start..n]);
-                if was_empty { cond.notify_one(); }
-             }
+            producer.push_all_throttled(&bytes[..n], high);
+            stats.add_ingested(n);
         }
     }
-    
-    writeln!(&mut io::stderr(), "Attempting to join on the writer.").unwrap();
-    writer_handle.join().unwrap();
+}
+
+/// Writer loop for the default byte-stream mode: hold off until enough data is
+/// buffered, then drain up to `THREAD_BUFFER_SIZE` bytes of it in one shot. The draining threshold is
+/// the larger of the low watermark and the coalesce size, so a single blocking
+/// `pop_at_least` naturally batches a trickling producer's bytes into one
+/// `write()` without a second tiny pop.
+fn write_stream<W: Write>(consumer: &Consumer, output: &mut W, low: usize, coalesce: usize, stats: &Stats) {
+    let mut bytes: [u8; THREAD_BUFFER_SIZE] = [0; THREAD_BUFFER_SIZE];
+    let threshold = std::cmp::max(low, coalesce);
+    loop {
+        // Block until the threshold is reached; zero means the buffer has been
+        // closed and fully drained.
+        let n = consumer.pop_at_least(&mut bytes, threshold);
+        if n == 0 { break; }
+
+        // Write the data to stdout
+        let mut start = 0;
+        while start < n { start += output.write(&bytes[start..n]).unwrap(); }
+        output.flush().unwrap();
+        stats.add_emitted(n);
+    }
+}
+
+/// Reader loop for `--framed` mode: prefix each read from stdin with a 4-byte
+/// little-endian length header so the writer can reconstruct record boundaries.
+/// Records that could never fit in the buffer (header included) are rejected.
+fn read_framed<R: Read>(producer: &Producer, input: &mut R, stats: &Stats) {
+    let mut bytes: [u8; THREAD_BUFFER_SIZE] = [0; THREAD_BUFFER_SIZE];
+    loop {
+        let n = input.read(&mut bytes).unwrap();
+        if n == 0 { // input stream is closed
+            producer.close();
+            break;
+        }
+
+        if HEADER_SIZE + n > producer.capacity() {
+            writeln!(&mut io::stderr(),
+                     "Error: record of {} bytes is larger than the {}-byte buffer.",
+                     n, producer.capacity()).unwrap();
+            std::process::exit(1);
+        }
+
+        let header = [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8];
+        producer.push_all(&header);
+        producer.push_all(&bytes[..n]);
+        stats.add_ingested(HEADER_SIZE + n);
+    }
+}
+
+/// Writer loop for `--framed` mode: read a length header, wait for the whole
+/// payload to be buffered, then emit the record in a single `write_all` so a
+/// message is never split across two writes.
+fn write_framed<W: Write>(consumer: &Consumer, output: &mut W, stats: &Stats) {
+    let mut header = [0u8; HEADER_SIZE];
+    loop {
+        // A short read on the header means the stream closed between records.
+        if !fill(consumer, &mut header) { break; }
+        let len = (header[0] as usize)
+                | ((header[1] as usize) << 8)
+                | ((header[2] as usize) << 16)
+                | ((header[3] as usize) << 24);
+
+        let mut payload = vec![0u8; len];
+        if !fill(consumer, &mut payload) { break; }
+
+        output.write_all(&payload).unwrap();
+        output.flush().unwrap();
+        stats.add_emitted(HEADER_SIZE + len);
+    }
+}
+
+/// Fills `buf` completely from the consumer, blocking as needed. Returns `false`
+/// if the buffer closed before `buf` could be filled.
+fn fill(consumer: &Consumer, buf: &mut [u8]) -> bool {
+    let mut got = 0;
+    while got < buf.len() {
+        let n = consumer.pop_blocking(&mut buf[got..]);
+        if n == 0 { return false; }
+        got += n;
+    }
+    true
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 // Tests only beyond this point
 ////////////////////////////////////////////////////////////////////////////////
 
+/// A `Read` that hands back one predetermined chunk per `read()` call, so a
+/// framed round-trip test can control exactly where record boundaries fall.
+#[cfg(test)]
+struct ChunkReader { chunks: std::collections::VecDeque<Vec<u8>> }
+
+#[cfg(test)]
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.chunks.pop_front() {
+            Some(chunk) => { buf[..chunk.len()].copy_from_slice(&chunk); Ok(chunk.len()) }
+            None        => Ok(0),
+        }
+    }
+}
+
+/// A `Write` that records the bytes of every `write()` call, so a test can verify
+/// that `write_framed` emits one write per record and never splits a payload.
+#[cfg(test)]
+struct RecordingWriter { records: std::sync::Arc<std::sync::Mutex<Vec<Vec<u8>>>> }
+
+#[cfg(test)]
+impl Write for RecordingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.records.lock().unwrap().push(buf.to_vec());
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+#[test]
+fn test_framed_roundtrip_preserves_boundaries() {
+    // A 64-byte buffer with records whose headers+payloads repeatedly wrap past
+    // the end, including one payload that straddles the wrap point.
+    let sizes = [10usize, 50, 3, 40, 1, 55];
+    let inputs: Vec<Vec<u8>> = sizes.iter().enumerate()
+        .map(|(i, &len)| (0..len).map(|j| (i * 31 + j) as u8).collect())
+        .collect();
+
+    let (producer, consumer) = RingBuffer::new(64).split();
+    let stats = Stats::new(64);
+
+    let mut reader = ChunkReader { chunks: inputs.iter().cloned().collect() };
+    let read_stats = stats.clone();
+    let reader_handle = thread::spawn(move || {
+        read_framed(&producer, &mut reader, &read_stats);
+    });
+
+    let records = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut writer = RecordingWriter { records: records.clone() };
+    write_framed(&consumer, &mut writer, &stats);
+
+    reader_handle.join().unwrap();
+
+    let got = records.lock().unwrap();
+    assert_eq!(&*got, &inputs, "each record must arrive whole and in order");
+}
+
 #[test]
-fn test_parse_mem_bytes() -> () {
+fn test_parse_mem_bytes() {
     assert!(parse_memory("1") == Some::<usize>(1));
     assert!(parse_memory("1024") == Some::<usize>(1024));
     assert!(parse_memory("1000000000") == Some::<usize>(1000000000));
-    assert!(parse_memory("10000000000000000000000000000") == None);
+    assert!(parse_memory("10000000000000000000000000000").is_none());
 }
 
 #[test]
-fn test_parse_mem_suffixed() -> () {
+fn test_parse_mem_suffixed() {
     assert!(parse_memory("1k")      == Some::<usize>(1024));
     assert!(parse_memory("99k")     == Some::<usize>(99 * 1024));
     assert!(parse_memory("99kb")    == Some::<usize>(99 * 1024));
@@ -188,15 +398,15 @@ fn test_parse_mem_suffixed() -> () {
 }
 
 #[test]
-fn test_parse_mem_fails() -> () {
-    assert!(parse_memory("") == None);
-    assert!(parse_memory("k") == None);
-    assert!(parse_memory("kb") == None);
-    assert!(parse_memory("foo") == None);
-    assert!(parse_memory("not1024m") == None);
-    assert!(parse_memory("-12g") == None);
-    assert!(parse_memory("12x") == None);
-    assert!(parse_memory("7y") == None);
-    assert!(parse_memory("1024x1024") == None);
-    assert!(parse_memory("1024mi") == None);
+fn test_parse_mem_fails() {
+    assert!(parse_memory("").is_none());
+    assert!(parse_memory("k").is_none());
+    assert!(parse_memory("kb").is_none());
+    assert!(parse_memory("foo").is_none());
+    assert!(parse_memory("not1024m").is_none());
+    assert!(parse_memory("-12g").is_none());
+    assert!(parse_memory("12x").is_none());
+    assert!(parse_memory("7y").is_none());
+    assert!(parse_memory("1024x1024").is_none());
+    assert!(parse_memory("1024mi").is_none());
 }
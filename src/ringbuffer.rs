@@ -0,0 +1,552 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//!
+//! A wait-free single-producer/single-consumer byte queue.
+//!
+//! There is exactly one producer (the stdin reader) and one consumer (the
+//! stdout writer), so the buffer does not need a lock to coordinate them. The
+//! storage is a fixed, power-of-two sized byte array indexed by a free-running
+//! `head` (read) and `tail` (write) cursor. Occupancy is the wrapping
+//! difference of the two cursors and slots are located with a bitwise `AND`
+//! against the capacity mask rather than a modulo.
+//!
+//! Each side only ever writes its own cursor. The producer publishes new data
+//! with a `Release` store to `tail` and the consumer observes it with an
+//! `Acquire` load (and vice-versa for free space), which is all the
+//! synchronisation a SPSC queue requires. The two cursors live on separate
+//! cache lines to keep the producer and consumer from ping-ponging a shared
+//! line back and forth. When a side has to wait it parks itself and is woken by
+//! an `unpark` from the other side. `put` and `get` take no lock; the blocking
+//! methods lock a small mutex around the other side's thread handle each time
+//! they wake it, which they do after every transfer that moves data.
+//!
+
+use std::cell::UnsafeCell;
+use std::cmp;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
+#[cfg(feature = "async")]
+use std::task::Waker;
+use std::thread::{self, Thread};
+
+/// Wrapper that forces its contents onto their own 64-byte cache line so the
+/// producer's `tail` and the consumer's `head` never share a line and thrash
+/// each other through false sharing.
+#[repr(align(64))]
+struct CacheAligned<T>(T);
+
+/// A fixed-capacity, wait-free SPSC ring buffer of bytes.
+pub struct RingBuffer {
+    /// Backing storage, one `UnsafeCell` per byte. Access goes through raw
+    /// pointers and `copy_nonoverlapping` only — the two sides never form a `&`
+    /// or `&mut` to the allocation, so there is no aliasing even though the
+    /// producer and consumer touch disjoint ranges concurrently.
+    buf: Box<[UnsafeCell<u8>]>,
+    /// `capacity - 1`; maps a free-running cursor onto a slot with `& mask`.
+    mask: usize,
+    /// Read cursor, advanced only by the consumer.
+    head: CacheAligned<AtomicUsize>,
+    /// Write cursor, advanced only by the producer.
+    tail: CacheAligned<AtomicUsize>,
+    /// Set once the producer has seen EOF and will write no more.
+    closed: AtomicBool,
+    /// Handle of the producer thread, so the consumer can wake it when space
+    /// frees up. Locked each time the consumer wakes the producer, which the
+    /// blocking reads do after every non-empty `get`.
+    producer: Mutex<Option<Thread>>,
+    /// Handle of the consumer thread, so the producer can wake it when data
+    /// arrives (or the buffer closes).
+    consumer: Mutex<Option<Thread>>,
+    /// `Waker` of an async producer task parked waiting for space. SPSC means a
+    /// single slot per side is enough.
+    #[cfg(feature = "async")]
+    producer_waker: Mutex<Option<Waker>>,
+    /// `Waker` of an async consumer task parked waiting for data.
+    #[cfg(feature = "async")]
+    consumer_waker: Mutex<Option<Waker>>,
+}
+
+// The cursors provide all the cross-thread synchronisation; the `UnsafeCell`
+// is only ever touched by a single side for any given slot.
+unsafe impl Sync for RingBuffer {}
+unsafe impl Send for RingBuffer {}
+
+impl RingBuffer {
+    /// Creates a new buffer able to hold at least `size` bytes. The capacity is
+    /// rounded up to the next power of two so occupancy can be computed with a
+    /// mask instead of a modulo.
+    pub fn new(size: usize) -> RingBuffer {
+        let capacity = cmp::max(2, size.next_power_of_two());
+        let buf = (0..capacity).map(|_| UnsafeCell::new(0u8)).collect::<Vec<_>>().into_boxed_slice();
+        RingBuffer {
+            buf,
+            mask: capacity - 1,
+            head: CacheAligned(AtomicUsize::new(0)),
+            tail: CacheAligned(AtomicUsize::new(0)),
+            closed: AtomicBool::new(false),
+            producer: Mutex::new(None),
+            consumer: Mutex::new(None),
+            #[cfg(feature = "async")]
+            producer_waker: Mutex::new(None),
+            #[cfg(feature = "async")]
+            consumer_waker: Mutex::new(None),
+        }
+    }
+
+    /// The total capacity of the buffer in bytes.
+    pub fn capacity(&self) -> usize {
+        self.mask + 1
+    }
+
+    /// The number of bytes currently available to the consumer.
+    fn len(&self) -> usize {
+        let tail = self.tail.0.load(Ordering::Acquire);
+        let head = self.head.0.load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+
+    /// Returns `true` if there is nothing for the consumer to read.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` if there is no room for the producer to write.
+    pub fn is_full(&self) -> bool {
+        self.len() == self.capacity()
+    }
+
+    /// Returns `true` once the producer has closed the buffer.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Copies as many bytes from `src` as will fit into the free region and
+    /// publishes them to the consumer. Returns the number of bytes written,
+    /// which may be zero if the buffer is full. Producer side only.
+    pub fn put(&self, src: &[u8]) -> usize {
+        let tail = self.tail.0.load(Ordering::Relaxed);
+        let head = self.head.0.load(Ordering::Acquire);
+        let free = self.capacity() - tail.wrapping_sub(head);
+        let n = cmp::min(free, src.len());
+
+        let base = self.buf.as_ptr() as *mut u8;
+        let start = tail & self.mask;
+        let first = cmp::min(n, self.capacity() - start);
+        unsafe {
+            ptr::copy_nonoverlapping(src.as_ptr(), base.add(start), first);
+            if first < n {
+                ptr::copy_nonoverlapping(src.as_ptr().add(first), base, n - first);
+            }
+        }
+
+        self.tail.0.store(tail.wrapping_add(n), Ordering::Release);
+        n
+    }
+
+    /// Copies up to `dst.len()` bytes out of the occupied region and frees the
+    /// space back to the producer. Returns the number of bytes read, which may
+    /// be zero if the buffer is empty. Consumer side only.
+    pub fn get(&self, dst: &mut [u8]) -> usize {
+        let head = self.head.0.load(Ordering::Relaxed);
+        let tail = self.tail.0.load(Ordering::Acquire);
+        let n = cmp::min(tail.wrapping_sub(head), dst.len());
+
+        let base = self.buf.as_ptr() as *const u8;
+        let start = head & self.mask;
+        let first = cmp::min(n, self.capacity() - start);
+        unsafe {
+            ptr::copy_nonoverlapping(base.add(start), dst.as_mut_ptr(), first);
+            if first < n {
+                ptr::copy_nonoverlapping(base, dst.as_mut_ptr().add(first), n - first);
+            }
+        }
+
+        self.head.0.store(head.wrapping_add(n), Ordering::Release);
+        n
+    }
+
+    /// Marks the buffer closed and wakes the consumer so it can drain the tail
+    /// of the stream and exit.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.unpark_consumer();
+        #[cfg(feature = "async")]
+        self.wake_consumer_task();
+    }
+
+    /// Parks the calling async producer task until the consumer frees space.
+    #[cfg(feature = "async")]
+    pub fn register_producer_waker(&self, waker: &Waker) {
+        *self.producer_waker.lock().unwrap() = Some(waker.clone());
+    }
+
+    /// Parks the calling async consumer task until the producer supplies data.
+    #[cfg(feature = "async")]
+    pub fn register_consumer_waker(&self, waker: &Waker) {
+        *self.consumer_waker.lock().unwrap() = Some(waker.clone());
+    }
+
+    /// Wakes an async producer task waiting for space, if any.
+    #[cfg(feature = "async")]
+    pub fn wake_producer_task(&self) {
+        if let Some(waker) = self.producer_waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+
+    /// Wakes an async consumer task waiting for data, if any.
+    #[cfg(feature = "async")]
+    pub fn wake_consumer_task(&self) {
+        if let Some(waker) = self.consumer_waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+
+    /// Remembers the calling thread as the consumer so the producer can wake it.
+    fn register_consumer(&self) {
+        *self.consumer.lock().unwrap() = Some(thread::current());
+    }
+
+    /// Remembers the calling thread as the producer so the consumer can wake it.
+    fn register_producer(&self) {
+        *self.producer.lock().unwrap() = Some(thread::current());
+    }
+
+    /// Wakes a parked consumer, if any.
+    fn unpark_consumer(&self) {
+        if let Some(ref t) = *self.consumer.lock().unwrap() {
+            t.unpark();
+        }
+    }
+
+    /// Wakes a parked producer, if any.
+    fn unpark_producer(&self) {
+        if let Some(ref t) = *self.producer.lock().unwrap() {
+            t.unpark();
+        }
+    }
+
+    /// Copies the whole of `src` into the buffer, parking the producer whenever
+    /// the buffer fills and resuming once the consumer has made room.
+    pub fn put_all(&self, src: &[u8]) {
+        let mut start = 0;
+        while start < src.len() {
+            let n = self.put(&src[start..]);
+            start += n;
+            // Wake the consumer whenever we add data so a side blocked on a low
+            // watermark sees the level it is waiting for.
+            if n > 0 {
+                self.unpark_consumer();
+            }
+            if start < src.len() {
+                // Register before the final full-check so a drain that happens
+                // between the check and the park still delivers its unpark.
+                self.register_producer();
+                if self.is_full() {
+                    thread::park();
+                }
+            }
+        }
+    }
+
+    /// Blocks until at least one byte is available, then copies out up to
+    /// `dst.len()` bytes. Returns `0` only once the buffer is closed and drained.
+    pub fn get_blocking(&self, dst: &mut [u8]) -> usize {
+        loop {
+            // Sample `closed` before draining: once it is seen set, every byte
+            // pushed before the close is visible to this `get`.
+            let closed = self.is_closed();
+            let n = self.get(dst);
+            if n > 0 {
+                // Wake the producer unconditionally: snapshotting `is_full`
+                // first would miss a producer that parked in the window between
+                // that read and this drain, deadlocking an SPSC pair.
+                self.unpark_producer();
+                return n;
+            }
+            if closed {
+                return 0;
+            }
+            self.register_consumer();
+            if self.is_empty() && !self.is_closed() {
+                thread::park();
+            }
+        }
+    }
+
+    /// Copies the whole of `src` into the buffer (blocking when full) and then,
+    /// once done, parks the producer until the consumer has drained the buffer
+    /// back below the `high` watermark. The whole of `src` goes in before the
+    /// watermark is checked, so the fill level can exceed `high` by up to
+    /// `src.len()` bytes.
+    pub fn put_all_throttled(&self, src: &[u8], high: usize) {
+        self.put_all(src);
+        loop {
+            if self.len() < high || self.is_closed() {
+                break;
+            }
+            self.register_producer();
+            if self.len() >= high && !self.is_closed() {
+                thread::park();
+            }
+        }
+    }
+
+    /// Blocks until at least `low` bytes are buffered (or the buffer is closed),
+    /// then copies out up to `dst.len()` bytes. This lets the consumer hold off
+    /// until a worthwhile batch has accumulated instead of emitting every
+    /// trickle. Returns `0` only once the buffer is closed and drained.
+    pub fn get_at_least(&self, dst: &mut [u8], low: usize) -> usize {
+        let want = cmp::max(low, 1);
+        loop {
+            if self.len() >= want || self.is_closed() {
+                let n = self.get(dst);
+                if n > 0 {
+                    self.unpark_producer();
+                    return n;
+                }
+                if self.is_closed() {
+                    return 0;
+                }
+            }
+            self.register_consumer();
+            if self.len() < want && !self.is_closed() {
+                thread::park();
+            }
+        }
+    }
+
+    /// Splits the buffer into its writing and reading halves, each sharing the
+    /// same backing storage through an `Arc`. Modelled on the `ringbuf` crate's
+    /// split interface so the two ends can live in different threads (or stages
+    /// of an in-process pipeline). `push_slice` and `pop_slice` take no lock;
+    /// the blocking methods lock the other side's thread handle to wake it.
+    pub fn split(self) -> (Producer, Consumer) {
+        let shared = Arc::new(self);
+        (Producer { shared: shared.clone() }, Consumer { shared })
+    }
+}
+
+/// The writing half of a split [`RingBuffer`]. Holds the only handle allowed to
+/// advance the write cursor, upholding the single-producer invariant.
+pub struct Producer {
+    shared: Arc<RingBuffer>,
+}
+
+impl Producer {
+    /// Copies as many bytes from `src` as currently fit and publishes them to
+    /// the consumer, returning the number written (zero when the buffer is
+    /// full). Non-blocking.
+    pub fn push_slice(&self, src: &[u8]) -> usize {
+        self.shared.put(src)
+    }
+
+    /// Copies the whole of `src` in, parking the producer whenever the buffer
+    /// fills and resuming once the consumer has made room.
+    pub fn push_all(&self, src: &[u8]) {
+        self.shared.put_all(src)
+    }
+
+    /// Like [`push_all`](Producer::push_all), but afterwards holds the producer
+    /// until the fill level drops back below the `high` watermark.
+    pub fn push_all_throttled(&self, src: &[u8], high: usize) {
+        self.shared.put_all_throttled(src, high)
+    }
+
+    /// Returns `true` if there is no room left to write.
+    pub fn is_full(&self) -> bool {
+        self.shared.is_full()
+    }
+
+    /// The total capacity of the underlying buffer in bytes.
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity()
+    }
+
+    /// Signals that no further data will be produced and wakes a parked
+    /// consumer so it can drain the tail of the stream.
+    pub fn close(&self) {
+        self.shared.close()
+    }
+}
+
+/// The reading half of a split [`RingBuffer`]. Holds the only handle allowed to
+/// advance the read cursor, upholding the single-consumer invariant.
+pub struct Consumer {
+    shared: Arc<RingBuffer>,
+}
+
+impl Consumer {
+    /// Copies up to `dst.len()` bytes out of the buffer and frees the space back
+    /// to the producer, returning the number read (zero when empty). Non-blocking.
+    pub fn pop_slice(&self, dst: &mut [u8]) -> usize {
+        self.shared.get(dst)
+    }
+
+    /// Blocks until at least one byte is available, then copies out up to
+    /// `dst.len()` bytes. Returns `0` only once the buffer is closed and drained.
+    pub fn pop_blocking(&self, dst: &mut [u8]) -> usize {
+        self.shared.get_blocking(dst)
+    }
+
+    /// Blocks until at least `low` bytes are buffered (or the buffer is closed),
+    /// then copies out up to `dst.len()` bytes. Returns `0` only once the buffer
+    /// is closed and drained.
+    pub fn pop_at_least(&self, dst: &mut [u8], low: usize) -> usize {
+        self.shared.get_at_least(dst, low)
+    }
+
+    /// Returns `true` if there is nothing to read right now.
+    pub fn is_empty(&self) -> bool {
+        self.shared.is_empty()
+    }
+
+    /// Returns `true` once the producer has closed its half.
+    pub fn is_closed(&self) -> bool {
+        self.shared.is_closed()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Tests only beyond this point
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn test_capacity_rounds_up_to_power_of_two() {
+    assert!(RingBuffer::new(1000).capacity() == 1024);
+    assert!(RingBuffer::new(1024).capacity() == 1024);
+    assert!(RingBuffer::new(1025).capacity() == 2048);
+}
+
+#[test]
+fn test_put_then_get_roundtrips() {
+    let ring = RingBuffer::new(8);
+    assert!(ring.is_empty());
+    assert!(ring.put(b"hello") == 5);
+    assert!(!ring.is_empty());
+
+    let mut out = [0u8; 16];
+    assert!(ring.get(&mut out) == 5);
+    assert!(&out[..5] == b"hello");
+    assert!(ring.is_empty());
+}
+
+#[test]
+fn test_put_saturates_at_capacity() {
+    let ring = RingBuffer::new(4);
+    assert!(ring.put(b"abcdef") == 4);
+    assert!(ring.is_full());
+    assert!(ring.put(b"x") == 0);
+}
+
+#[test]
+fn test_get_wraps_around_the_end() {
+    let ring = RingBuffer::new(4);
+    let mut out = [0u8; 4];
+
+    assert!(ring.put(b"abc") == 3);
+    assert!(ring.get(&mut out[..2]) == 2);
+    assert!(&out[..2] == b"ab");
+
+    // head is now at 2, tail at 3; this write must straddle the wrap point.
+    assert!(ring.put(b"de") == 2);
+    assert!(ring.get(&mut out) == 3);
+    assert!(&out[..3] == b"cde");
+}
+
+#[test]
+fn test_split_roundtrips_through_handles() {
+    let (producer, consumer) = RingBuffer::new(8).split();
+    assert!(consumer.is_empty());
+    assert!(producer.push_slice(b"frame") == 5);
+    assert!(!consumer.is_empty());
+
+    let mut out = [0u8; 16];
+    assert!(consumer.pop_slice(&mut out) == 5);
+    assert!(&out[..5] == b"frame");
+    assert!(consumer.is_empty());
+}
+
+#[test]
+fn test_close_is_visible_to_consumer() {
+    let (producer, consumer) = RingBuffer::new(4).split();
+    assert!(!consumer.is_closed());
+    producer.close();
+    assert!(consumer.is_closed());
+}
+
+#[test]
+fn test_blocking_roundtrip_across_threads() {
+    // Push far more than the buffer holds so the producer repeatedly fills the
+    // buffer and blocks, exercising the put_all/get_blocking park/unpark dance.
+    let (producer, consumer) = RingBuffer::new(64).split();
+    let total = 64 * 200;
+
+    let writer = thread::spawn(move || {
+        let data: Vec<u8> = (0..total).map(|i| (i % 251) as u8).collect();
+        producer.push_all(&data);
+        producer.close();
+    });
+
+    // An odd read size keeps the wrap-around paths busy.
+    let mut out = Vec::new();
+    let mut buf = [0u8; 7];
+    loop {
+        let n = consumer.pop_blocking(&mut buf);
+        if n == 0 { break; }
+        out.extend_from_slice(&buf[..n]);
+    }
+    writer.join().unwrap();
+
+    let expected: Vec<u8> = (0..total).map(|i| (i % 251) as u8).collect();
+    assert!(out == expected);
+}
+
+#[test]
+fn test_get_at_least_roundtrip_across_threads() {
+    // A trickling producer plus a low watermark forces get_at_least to wait and
+    // be woken; EOF must still flush the final sub-watermark bytes.
+    let (producer, consumer) = RingBuffer::new(64).split();
+    let total = 1000;
+
+    let writer = thread::spawn(move || {
+        for i in 0..total {
+            producer.push_all(&[(i % 251) as u8]);
+        }
+        producer.close();
+    });
+
+    let mut out = Vec::new();
+    let mut buf = [0u8; 32];
+    loop {
+        let n = consumer.pop_at_least(&mut buf, 16);
+        if n == 0 { break; }
+        out.extend_from_slice(&buf[..n]);
+    }
+    writer.join().unwrap();
+
+    let expected: Vec<u8> = (0..total).map(|i| (i % 251) as u8).collect();
+    assert!(out == expected);
+}
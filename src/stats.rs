@@ -0,0 +1,132 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Tim Fennell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//!
+//! Opt-in throughput reporting for `--stats` mode. Following the heartbeat idea
+//! from the aeron ring buffer, the reader and writer threads only ever bump an
+//! `AtomicUsize` as they transfer bytes; a separate monitor thread samples those
+//! counters every so often and does all the rate arithmetic off the hot path.
+//!
+
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Counters shared between the transfer threads and the monitor. The fill level
+/// is the difference of the two totals, so no separate occupancy counter (or
+/// lock) is needed.
+pub struct Stats {
+    /// Total bytes the reader has pushed into the buffer.
+    ingested: AtomicUsize,
+    /// Total bytes the writer has pulled out of the buffer.
+    emitted: AtomicUsize,
+    /// Buffer capacity, used for the fill-level percentage.
+    capacity: usize,
+    /// Set once both streams are done so the monitor can print a final line.
+    done: AtomicBool,
+}
+
+impl Stats {
+    /// Creates a fresh set of counters for a buffer of the given capacity.
+    pub fn new(capacity: usize) -> Arc<Stats> {
+        Arc::new(Stats {
+            ingested: AtomicUsize::new(0),
+            emitted: AtomicUsize::new(0),
+            capacity,
+            done: AtomicBool::new(false),
+        })
+    }
+
+    /// Records `n` bytes pushed into the buffer by the reader.
+    pub fn add_ingested(&self, n: usize) {
+        self.ingested.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Records `n` bytes pulled out of the buffer by the writer.
+    pub fn add_emitted(&self, n: usize) {
+        self.emitted.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Signals that both streams have finished so the monitor can stop.
+    pub fn finish(&self) {
+        self.done.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Spawns the monitor thread. It samples the counters every `interval`,
+/// printing totals, instantaneous and average rates, and the current fill
+/// percentage to stderr, then prints one final line once the transfer is done.
+pub fn spawn(stats: Arc<Stats>, interval: Duration) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let start = Instant::now();
+        let mut last = start;
+        let mut last_out = 0usize;
+        loop {
+            thread::sleep(interval);
+            let done = stats.done.load(Ordering::Relaxed);
+
+            let now = Instant::now();
+            let ingested = stats.ingested.load(Ordering::Relaxed);
+            let emitted = stats.emitted.load(Ordering::Relaxed);
+
+            let instant_dt = secs(now.duration_since(last));
+            let total_dt = secs(now.duration_since(start));
+            let rate = (emitted - last_out) as f64 / instant_dt;
+            let avg = emitted as f64 / total_dt;
+            // `ingested` and `emitted` are sampled separately, so the writer may
+            // have raced ahead of the reader snapshot; saturate to avoid a usize
+            // underflow panic (debug) or garbage percentage (release).
+            let buffered = ingested.saturating_sub(emitted);
+            let fill = buffered as f64 / stats.capacity as f64 * 100.0;
+
+            writeln!(&mut io::stderr(),
+                     "pipebuffer: in {} out {} rate {}/s avg {}/s fill {:.1}%",
+                     human(ingested as f64), human(emitted as f64),
+                     human(rate), human(avg), fill).unwrap();
+
+            if done { break; }
+            last = now;
+            last_out = emitted;
+        }
+    })
+}
+
+/// Converts a `Duration` to seconds as a float, clamped away from zero so it is
+/// always safe to divide by.
+fn secs(d: Duration) -> f64 {
+    let s = d.as_secs() as f64 + d.subsec_nanos() as f64 / 1_000_000_000.0;
+    if s > 0.0 { s } else { 1e-9 }
+}
+
+/// Formats a byte count in binary units, e.g. `1536` becomes `1.5 KiB`.
+fn human(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}